@@ -1,12 +1,17 @@
-use cargo_valgrind::{build_target, targets, valgrind, Build, Leak, Target};
+use cargo_valgrind::{
+    targets, test_targets, valgrind, Build, Features, Leak, PackageSelection, Target,
+    TargetFilter, TestFilter,
+};
 use clap::{crate_authors, crate_name, crate_version, App, Arg, ArgMatches};
 use colored::Colorize;
+use serde::Serialize;
 use std::path::{Path, PathBuf};
 
 /// The Result type for this application.
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 /// The result of the valgrind run.
+#[derive(Clone, Copy)]
 enum Report {
     /// The analyzed binary contains leaks.
     ContainsErrors,
@@ -14,11 +19,21 @@ enum Report {
     NoErrorDetected,
 }
 
+/// How leak reports are printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// Colored, human-readable text (the default).
+    Human,
+    /// One JSON object per line, mirroring the style of cargo's own
+    /// `--message-format=json` diagnostics, for consumption by editors and CI.
+    Json,
+}
+
 /// Build the command line interface.
 ///
 /// The CLI currently supports the distinction between debug and release builds
 /// (selected via the `--release` flag) as well as the selection of the target
-/// to execute. Currently binaries, examples and benches are supported.
+/// to execute. Binaries, examples, benches and test harnesses are supported.
 fn cli<'a, 'b>() -> App<'a, 'b> {
     App::new(crate_name!())
         .about("Cargo subcommand for running valgrind")
@@ -53,6 +68,26 @@ fn cli<'a, 'b>() -> App<'a, 'b> {
                 .value_name("NAME")
                 .conflicts_with_all(&["bin", "example"]),
         )
+        .arg(
+            Arg::with_name("test")
+                .help("Build and run the specified test harness")
+                .long("test")
+                .takes_value(true)
+                .value_name("NAME")
+                .conflicts_with_all(&["bin", "example", "bench", "tests"]),
+        )
+        .arg(
+            Arg::with_name("tests")
+                .help("Build and run every test harness")
+                .long("tests")
+                .conflicts_with_all(&["bin", "example", "bench", "test"]),
+        )
+        .arg(
+            Arg::with_name("harness-args")
+                .help("Arguments forwarded to the test harness, to select which tests run")
+                .multiple(true)
+                .last(true),
+        )
         .arg(
             Arg::with_name("manifest")
                 .help("Path to Cargo.toml")
@@ -60,6 +95,58 @@ fn cli<'a, 'b>() -> App<'a, 'b> {
                 .takes_value(true)
                 .value_name("PATH"),
         )
+        .arg(
+            Arg::with_name("output-format")
+                .help("The format in which the leak report is printed")
+                .long("output-format")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(&["human", "json"])
+                .default_value("human"),
+        )
+        .arg(
+            Arg::with_name("features")
+                .help("Space or comma separated list of features to activate")
+                .long("features")
+                .takes_value(true)
+                .value_name("FEATURES")
+                .conflicts_with("all-features"),
+        )
+        .arg(
+            Arg::with_name("all-features")
+                .help("Activate all available features")
+                .long("all-features"),
+        )
+        .arg(
+            Arg::with_name("no-default-features")
+                .help("Do not activate the `default` feature")
+                .long("no-default-features"),
+        )
+        .arg(
+            Arg::with_name("workspace")
+                .help("Analyze every member of the workspace")
+                .long("workspace"),
+        )
+        .arg(
+            Arg::with_name("package")
+                .help("Package(s) to analyze")
+                .long("package")
+                .short("p")
+                .takes_value(true)
+                .value_name("SPEC")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("exclude")
+                .help("Exclude package(s) from the analysis")
+                .long("exclude")
+                .takes_value(true)
+                .value_name("SPEC")
+                .multiple(true)
+                .number_of_values(1)
+                .requires("workspace"),
+        )
 }
 
 /// Query the build type (debug/release) from the the command line parameters.
@@ -86,42 +173,164 @@ fn manifest(parameters: &ArgMatches) -> Result<PathBuf> {
     Ok(manifest)
 }
 
-/// Query the specified `Target`, if any.
-fn specified_target(parameters: &ArgMatches) -> Option<Target> {
+/// Query the feature selection from the the command line parameters.
+fn features(parameters: &ArgMatches) -> Features {
+    Features {
+        features: parameters
+            .value_of("features")
+            .map(|features| {
+                features
+                    .split(|c: char| c == ' ' || c == ',')
+                    .filter(|feature| !feature.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default(),
+        all_features: parameters.is_present("all-features"),
+        no_default_features: parameters.is_present("no-default-features"),
+    }
+}
+
+/// Query the workspace package selection from the the command line
+/// parameters.
+fn package_selection(parameters: &ArgMatches) -> PackageSelection {
+    PackageSelection {
+        workspace: parameters.is_present("workspace"),
+        package: parameters
+            .values_of("package")
+            .map(|values| values.map(String::from).collect())
+            .unwrap_or_default(),
+        exclude: parameters
+            .values_of("exclude")
+            .map(|values| values.map(String::from).collect())
+            .unwrap_or_default(),
+    }
+}
+
+/// Query the test harness filter from the the command line parameters.
+fn test_filter(parameters: &ArgMatches) -> TestFilter {
+    TestFilter {
+        tests: parameters
+            .value_of("test")
+            .map(|test| vec![test.to_string()])
+            .unwrap_or_default(),
+        all_tests: parameters.is_present("tests"),
+    }
+}
+
+/// Query the output format from the the command line parameters.
+fn output_format(parameters: &ArgMatches) -> OutputFormat {
+    match parameters.value_of("output-format") {
+        Some("json") => OutputFormat::Json,
+        _ => OutputFormat::Human,
+    }
+}
+
+/// Query the arguments to forward to the test harness, if any.
+fn harness_args(parameters: &ArgMatches) -> Vec<String> {
+    parameters
+        .values_of("harness-args")
+        .map(|values| values.map(String::from).collect())
+        .unwrap_or_default()
+}
+
+/// A target requested on the command line, identified by kind and name.
+///
+/// Unlike [`Target`](cargo_valgrind::Target), this only carries the name the
+/// user passed to e.g. `--bin <NAME>`, not the on-disk path of the built
+/// executable, since that path is only known once `targets()`/`test_targets()`
+/// have actually run.
+enum RequestedTarget {
+    Binary(String),
+    Example(String),
+    Benchmark(String),
+    Test(String),
+}
+impl RequestedTarget {
+    /// Turn this into a [`TargetFilter`](cargo_valgrind::TargetFilter), to
+    /// restrict the discovery build to the requested bin/example/bench.
+    ///
+    /// Returns `None` for `RequestedTarget::Test`, since test harnesses are
+    /// discovered via `test_targets()`/`TestFilter` instead.
+    fn as_filter(&self) -> Option<TargetFilter> {
+        match self {
+            RequestedTarget::Binary(name) => Some(TargetFilter::Binary(name.clone())),
+            RequestedTarget::Example(name) => Some(TargetFilter::Example(name.clone())),
+            RequestedTarget::Benchmark(name) => Some(TargetFilter::Benchmark(name.clone())),
+            RequestedTarget::Test(_) => None,
+        }
+    }
+}
+
+/// Query the specified `RequestedTarget`, if any.
+fn specified_target(parameters: &ArgMatches) -> Option<RequestedTarget> {
     parameters
         .value_of("bin")
-        .map(|path| Target::Binary(PathBuf::from(path)))
+        .map(|name| RequestedTarget::Binary(name.to_string()))
         .or(parameters
             .value_of("example")
-            .map(|path| Target::Example(PathBuf::from(path))))
+            .map(|name| RequestedTarget::Example(name.to_string())))
         .or(parameters
             .value_of("bench")
-            .map(|path| Target::Benchmark(PathBuf::from(path))))
+            .map(|name| RequestedTarget::Benchmark(name.to_string())))
+        .or(parameters
+            .value_of("test")
+            .map(|name| RequestedTarget::Test(name.to_string())))
 }
 
-/// Search for the actual binary to analyze.
+/// Search for the actual binaries to analyze.
 ///
 /// This function takes the output of `specified_target()`, as well as the list
-/// of all possible targets returned by `targets()`. It searches, if the
-/// requested binary exists. If no binary was specified and there is only one
-/// target available, that target is used.
+/// of all possible targets returned by `targets()`/`test_targets()`. It
+/// searches, if the requested binary exists, matching by kind and name (the
+/// on-disk file name of a target, e.g. a hash-suffixed test harness, does not
+/// necessarily match the name the user specified). If no binary was specified
+/// and there is only one target available, that target is used. If
+/// `spans_multiple_packages` is set (a workspace-wide selection via
+/// `--workspace`/`--package`, or `--tests`) and no specific binary was
+/// requested, every target in that selection is analyzed.
 ///
 /// # Errors
-/// This function returns an error, if there is no target specified and there
-/// are multiple targets to choose from, or if the user specified a non-existing
-/// target.
-fn find_target(specified: Option<Target>, targets: &[Target]) -> Result<Target> {
-    let target = match specified {
-        Some(path) => path,
-        None if targets.len() == 1 => targets[0].clone(),
-        None => Err("Multiple possible targets, please specify more precise")?,
-    };
-    let target = targets
-        .into_iter()
-        .find(|&path| path == &target)
-        .cloned()
-        .ok_or("Could not find selected binary")?;
-    Ok(target)
+/// This function returns an error, if there is no target specified,
+/// `spans_multiple_packages` is not set and there are multiple targets to
+/// choose from, or if the user specified a non-existing target.
+fn find_targets(
+    specified: Option<RequestedTarget>,
+    spans_multiple_packages: bool,
+    targets: &[Target],
+) -> Result<Vec<Target>> {
+    if let Some(requested) = specified {
+        let target = targets
+            .iter()
+            .find(|target| match (&requested, *target) {
+                (RequestedTarget::Binary(name), Target::Binary { name: target_name, .. }) => {
+                    name == target_name
+                }
+                (RequestedTarget::Example(name), Target::Example { name: target_name, .. }) => {
+                    name == target_name
+                }
+                (
+                    RequestedTarget::Benchmark(name),
+                    Target::Benchmark {
+                        name: target_name, ..
+                    },
+                ) => name == target_name,
+                (RequestedTarget::Test(name), Target::Test { name: target_name, .. }) => {
+                    name == target_name
+                }
+                _ => false,
+            })
+            .cloned()
+            .ok_or("Could not find selected binary")?;
+        return Ok(vec![target]);
+    }
+    if spans_multiple_packages {
+        return Ok(targets.to_vec());
+    }
+    match targets {
+        [target] => Ok(vec![target.clone()]),
+        _ => Err("Multiple possible targets, please specify more precise")?,
+    }
 }
 
 /// Display a single `Leak` to the console.
@@ -137,35 +346,130 @@ fn display_error(leak: Leak) {
     }
 }
 
+/// A single back-trace entry of a [`JsonLeak`](struct.JsonLeak.html), mirroring
+/// the `function`/`file`/`line` triple of a valgrind stack frame.
+#[derive(Debug, Serialize)]
+struct JsonFrame {
+    function: String,
+    file: Option<String>,
+    line: Option<u32>,
+}
+
+/// The JSON representation of a single `Leak`, emitted one per line when
+/// `--output-format=json` is selected.
+#[derive(Debug, Serialize)]
+struct JsonLeak {
+    target: String,
+    leaked_bytes: usize,
+    kind: String,
+    back_trace: Vec<JsonFrame>,
+}
+
+/// The final, per-target JSON record, emitted after all of its `Leak`s.
+#[derive(Debug, Serialize)]
+struct JsonSummary {
+    target: String,
+    leak_count: usize,
+    status: &'static str,
+}
+
+/// Print a single `Leak` as one JSON object line.
+fn display_error_json(leak: Leak, target_path: &str) {
+    let leak = JsonLeak {
+        target: target_path.to_string(),
+        leaked_bytes: leak.leaked_bytes(),
+        kind: leak.kind().to_string(),
+        back_trace: leak
+            .back_trace()
+            .map(|frame| JsonFrame {
+                function: frame.function().to_string(),
+                file: frame.file().map(ToString::to_string),
+                line: frame.line(),
+            })
+            .collect(),
+    };
+    println!("{}", serde_json::to_string(&leak).unwrap_or_default());
+}
+
 /// Run the specified target inside of valgrind and print the output.
-fn analyze_target(target: &Target, manifest: &Path) -> Result<Report> {
+///
+/// `harness_args` is forwarded to the target itself, so that it can e.g. be
+/// used to scope which tests a [`Target::Test`](enum.Target.html#variant.Test)
+/// harness runs inside valgrind.
+fn analyze_target(
+    target: &Target,
+    manifest: &Path,
+    harness_args: &[String],
+    format: OutputFormat,
+) -> Result<Report> {
     let crate_root = manifest.parent().ok_or("Invalid empty manifest path")?;
     let target_path = target
         .path()
         .strip_prefix(crate_root)
         .map(|path| path.display().to_string())
         .unwrap_or_default();
-    println!("{:>12} `{}`", "Analyzing".green().bold(), target_path);
+    if format == OutputFormat::Human {
+        println!("{:>12} `{}`", "Analyzing".green().bold(), target_path);
+    }
 
-    let errors = valgrind(target.path())?;
-    if errors.is_empty() {
-        Ok(Report::NoErrorDetected)
+    let errors = valgrind(target.path(), harness_args)?;
+    let leak_count = errors.len();
+    if leak_count > 0 {
+        match format {
+            OutputFormat::Human => errors.into_iter().for_each(display_error),
+            OutputFormat::Json => errors
+                .into_iter()
+                .for_each(|leak| display_error_json(leak, &target_path)),
+        }
+    }
+    let report = if leak_count == 0 {
+        Report::NoErrorDetected
     } else {
-        errors.into_iter().for_each(display_error);
-        Ok(Report::ContainsErrors)
+        Report::ContainsErrors
+    };
+
+    if format == OutputFormat::Json {
+        let summary = JsonSummary {
+            target: target_path,
+            leak_count,
+            status: match report {
+                Report::ContainsErrors => "leaking",
+                Report::NoErrorDetected => "clean",
+            },
+        };
+        println!("{}", serde_json::to_string(&summary).unwrap_or_default());
     }
+    Ok(report)
 }
 
 fn run() -> Result<Report> {
     let cli = cli().get_matches();
     let build = build_type(&cli);
+    let features = features(&cli);
+    let packages = package_selection(&cli);
+    let test_filter = test_filter(&cli);
+    let harness_args = harness_args(&cli);
+    let format = output_format(&cli);
     let target = specified_target(&cli);
     let manifest = manifest(&cli)?;
 
-    let targets = targets(&manifest, build)?;
-    let target = find_target(target, &targets)?;
-    build_target(&manifest, build, target.clone())?;
-    analyze_target(&target, &manifest)
+    let wants_tests = !test_filter.tests.is_empty() || test_filter.all_tests;
+    let all_targets = if wants_tests {
+        test_targets(&manifest, build, &features, &packages, &test_filter)?
+    } else {
+        let target_filter = target.as_ref().and_then(RequestedTarget::as_filter);
+        targets(&manifest, build, &features, &packages, target_filter.as_ref())?
+    };
+    let spans_multiple = packages.spans_multiple_packages() || test_filter.all_tests;
+    let selected_targets = find_targets(target, spans_multiple, &all_targets)?;
+
+    let mut report = Report::NoErrorDetected;
+    for target in selected_targets {
+        if let Report::ContainsErrors = analyze_target(&target, &manifest, &harness_args, format)? {
+            report = Report::ContainsErrors;
+        }
+    }
+    Ok(report)
 }
 
 fn main() {
@@ -3,10 +3,11 @@ mod metadata;
 #[cfg(test)]
 mod tests;
 
+use serde::Deserialize;
 use std::{
-    io,
+    io::{self, BufRead, BufReader},
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Stdio},
 };
 
 /// The possible build types.
@@ -31,118 +32,350 @@ impl AsRef<Path> for Build {
     }
 }
 
-/// Query all binaries of the crate denoted by the given `Cargo.toml`.
+/// The crate feature selection, as passed to `cargo build`/`cargo test`.
 ///
-/// This function returns the paths to each executable in the given crate. Those
-/// are all the examples, benches as the actual crate binaries. This is based on
-/// the crate metadata obtained by [`metadata()`](fn.metadata.html).
+/// Since feature-gated targets are only discovered and built if the matching
+/// features are active, the same selection has to be applied consistently to
+/// every `cargo` invocation this crate makes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Features {
+    /// The features to activate, passed as a comma-separated `--features`
+    /// list.
+    pub features: Vec<String>,
+    /// Activate all available features (`--all-features`).
+    pub all_features: bool,
+    /// Do not activate the `default` feature (`--no-default-features`).
+    pub no_default_features: bool,
+}
+impl Features {
+    /// Append the `--features`/`--all-features`/`--no-default-features`
+    /// arguments represented by `self` to `command`.
+    fn apply(&self, command: &mut Command) {
+        if !self.features.is_empty() {
+            command.arg("--features").arg(self.features.join(","));
+        }
+        if self.all_features {
+            command.arg("--all-features");
+        }
+        if self.no_default_features {
+            command.arg("--no-default-features");
+        }
+    }
+}
+
+/// The subset of a workspace to analyze, as selected by `--workspace`,
+/// `--package` and `--exclude`.
 ///
-/// Only binaries of the specified manifest are returned. This means, that other
-/// crates in the same workspace may have binaries, but they are ignored.
+/// With the default (empty) selection, only the binaries of the crate
+/// denoted by the requested manifest are considered, exactly as before
+/// workspace support was added.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct PackageSelection {
+    /// Analyze every member of the workspace.
+    pub workspace: bool,
+    /// Only analyze the given package(s), by name.
+    pub package: Vec<String>,
+    /// Exclude the given package(s) from the analysis. Only meaningful
+    /// together with `workspace`.
+    pub exclude: Vec<String>,
+}
+impl PackageSelection {
+    /// Whether this selection spans more than the single requested crate,
+    /// i.e. whether `targets()` may return targets from multiple packages.
+    pub fn spans_multiple_packages(&self) -> bool {
+        self.workspace || !self.package.is_empty()
+    }
+
+    /// Append the `--workspace`/`--package`/`--exclude` arguments
+    /// represented by `self` to `command`.
+    fn apply(&self, command: &mut Command) {
+        if self.workspace {
+            command.arg("--workspace");
+        }
+        for package in &self.package {
+            command.arg("--package").arg(package);
+        }
+        for exclude in &self.exclude {
+            command.arg("--exclude").arg(exclude);
+        }
+    }
+}
+
+/// Which test harnesses to build, as selected by `--test`/`--tests`.
 ///
-/// Note, that plain tests and `custom-build` kinds currently are not supported.
+/// With the default (empty) selection, no test harnesses are built at all,
+/// exactly as before test support was added.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct TestFilter {
+    /// Only build the named test harness(es) (`--test <NAME>`).
+    pub tests: Vec<String>,
+    /// Build every test harness of the selected package(s) (`--tests`).
+    pub all_tests: bool,
+}
+impl TestFilter {
+    /// Append the `--test`/`--tests` arguments represented by `self` to
+    /// `command`.
+    fn apply(&self, command: &mut Command) {
+        if self.all_tests {
+            command.arg("--tests");
+        }
+        for test in &self.tests {
+            command.arg("--test").arg(test);
+        }
+    }
+}
+
+/// A single buildable artifact that `valgrind` can be run against.
 ///
-/// # Errors
-/// This function fails for the same reasons as the `metadata()` function.
+/// Every variant carries the cargo target name (as passed to e.g. `--bin
+/// <name>`) together with the path to the already built executable, as
+/// reported by `cargo` itself (see [`targets()`](fn.targets.html)), rather
+/// than a guessed location. The name has to be kept around separately from
+/// the path, since the on-disk file name of a target is not necessarily the
+/// target name: test harness executables in particular are hash-suffixed
+/// (e.g. `integration-a1b2c3`).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Target {
+    /// A plain binary, either `src/main.rs` or a `[[bin]]` target.
+    Binary { name: String, path: PathBuf },
+    /// An example, built from `examples/*.rs`.
+    Example { name: String, path: PathBuf },
+    /// A benchmark harness, built from `benches/*.rs`.
+    Benchmark { name: String, path: PathBuf },
+    /// A test harness, built from `tests/*.rs` or an inline `#[test]` module.
+    Test { name: String, path: PathBuf },
+}
+impl Target {
+    /// The path to the executable this target was built into.
+    pub fn path(&self) -> &Path {
+        match self {
+            Target::Binary { path, .. }
+            | Target::Example { path, .. }
+            | Target::Benchmark { path, .. }
+            | Target::Test { path, .. } => path,
+        }
+    }
+
+    /// The cargo target name, as passed to `--bin`/`--example`/`--bench`/`--test`.
+    pub fn name(&self) -> &str {
+        match self {
+            Target::Binary { name, .. }
+            | Target::Example { name, .. }
+            | Target::Benchmark { name, .. }
+            | Target::Test { name, .. } => name,
+        }
+    }
+}
+
+/// A single line of `cargo`'s `--message-format=json` output.
 ///
-/// # Panics
-/// This function currently panics, if a test or custom build binary is
-/// encountered.
-pub fn binaries<P: AsRef<Path>>(path: P, build: Build) -> Result<Vec<PathBuf>, io::Error> {
-    let package = metadata(&path)?;
-    let path = path.as_ref().canonicalize()?;
-    binaries_from(package, path, build)
+/// Only the fields required to discover build artifacts are modeled, all
+/// other messages (e.g. `"reason": "compiler-message"`) are skipped, because
+/// `executable` is simply absent from them and deserialization of this
+/// message is attempted for every kind of reason.
+#[derive(Debug, Deserialize)]
+struct BuildMessage {
+    reason: String,
+    /// The path to the produced executable, `None` for artifacts that do not
+    /// produce one (e.g. plain libraries).
+    executable: Option<PathBuf>,
+    target: BuildMessageTarget,
+    /// `None` for messages that carry no build profile at all (e.g.
+    /// `"reason": "compiler-message"`).
+    profile: Option<BuildMessageProfile>,
 }
 
-/// Query all binaries of given metadata.
+/// The `"target"` object embedded in a [`BuildMessage`](struct.BuildMessage.html).
+#[derive(Debug, Deserialize)]
+struct BuildMessageTarget {
+    name: String,
+    kind: Vec<metadata::Kind>,
+}
+
+/// The `"profile"` object embedded in a [`BuildMessage`](struct.BuildMessage.html).
+#[derive(Debug, Deserialize)]
+struct BuildMessageProfile {
+    /// Whether this artifact was compiled for `cargo test`/`cargo bench`,
+    /// i.e. it embeds a test/bench harness rather than being a plain build of
+    /// the target. This is the only way to tell a library crate's own
+    /// unit-test harness apart from a normal build of the library: both
+    /// report `target.kind == ["lib"]`.
+    test: bool,
+}
+
+impl BuildMessage {
+    /// Turn this message into a [`Target`](enum.Target.html), if it describes
+    /// a `compiler-artifact` with an executable cargo-valgrind knows how to
+    /// run.
+    fn into_target(self) -> Option<Target> {
+        if self.reason != "compiler-artifact" {
+            return None;
+        }
+        let path = self.executable?;
+        let name = self.target.name;
+        let kind = *self.target.kind.get(0)?;
+        // Bench harnesses also report `profile.test`, so they are told apart
+        // from test harnesses by their kind.
+        if kind != metadata::Kind::Bench && self.profile.map_or(false, |profile| profile.test) {
+            return Some(Target::Test { name, path });
+        }
+        match kind {
+            metadata::Kind::Bin => Some(Target::Binary { name, path }),
+            metadata::Kind::Example => Some(Target::Example { name, path }),
+            metadata::Kind::Bench => Some(Target::Benchmark { name, path }),
+            metadata::Kind::Test => Some(Target::Test { name, path }),
+            _ => None,
+        }
+    }
+}
+
+/// A single bin/example/bench requested on the command line, by name.
 ///
-/// See [`binaries()`](fn.binaries.html) for details.
+/// Threading this into [`targets()`](fn.targets.html) restricts the discovery
+/// build to the requested target, instead of compiling every bin, example
+/// and bench of the package: otherwise an unrelated broken example or bench
+/// would prevent analyzing a perfectly fine binary.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TargetFilter {
+    /// Only build the named binary (`--bin <NAME>`).
+    Binary(String),
+    /// Only build the named example (`--example <NAME>`).
+    Example(String),
+    /// Only build the named bench (`--bench <NAME>`).
+    Benchmark(String),
+}
+impl TargetFilter {
+    /// Append the `--bin`/`--example`/`--bench` argument represented by
+    /// `self` to `command`.
+    fn apply(&self, command: &mut Command) {
+        match self {
+            TargetFilter::Binary(name) => command.arg("--bin").arg(name),
+            TargetFilter::Example(name) => command.arg("--example").arg(name),
+            TargetFilter::Benchmark(name) => command.arg("--bench").arg(name),
+        };
+    }
+}
+
+/// Query all targets (binaries, examples, benches) of the crate denoted by
+/// the given `Cargo.toml`, or just the one named by `target` if given.
+///
+/// This runs `cargo build --message-format=json`, which reports the real
+/// on-disk path of every produced executable, instead of guessing it from
+/// `target_directory`, `build` and the target name. This is robust against a
+/// custom `CARGO_TARGET_DIR`, cross-compilation and hash-suffixed filenames.
 ///
-/// This is the real implementation of the `binaries()` function. It was added
-/// in order to be able to test this function without actual `Cargo.toml`s and
-/// by giving prepared metadata.
+/// Output lines that cannot be parsed as a build message are skipped.
 ///
-/// Note, that the path denoted by `requested` has to be canonicalized before.
-fn binaries_from<P: AsRef<Path>>(
-    package: metadata::Metadata,
-    requested: P,
+/// # Errors
+/// This function fails, if `cargo build` could not be spawned or if it exits
+/// with a non-zero status.
+pub fn targets<P: AsRef<Path>>(
+    manifest: P,
     build: Build,
-) -> Result<Vec<PathBuf>, io::Error> {
-    let target_dir = package.target_directory.join(build);
-    Ok(package
-        .packages
-        .into_iter()
-        .filter(|package| package.manifest_path == requested.as_ref())
-        .flat_map(|package| {
-            package
-                .targets
-                .into_iter()
-                .filter(|target| target.crate_types.contains(&metadata::CrateType::Binary))
-                .map(|target| {
-                    target_dir
-                        .join(match target.kind[0] {
-                            metadata::Kind::Binary => "",
-                            metadata::Kind::Example => "examples",
-                            metadata::Kind::Bench => "benches",
-                            metadata::Kind::Test | metadata::Kind::CustomBuild => unimplemented!(),
-                            metadata::Kind::Library
-                            | metadata::Kind::ProcMacro
-                            | metadata::Kind::DyLib
-                            | metadata::Kind::CDyLib
-                            | metadata::Kind::StaticLib
-                            | metadata::Kind::RLib => unreachable!("Non-binaries are filtered out"),
-                        })
-                        .join(target.name)
-                })
-        })
-        .collect())
+    features: &Features,
+    packages: &PackageSelection,
+    target: Option<&TargetFilter>,
+) -> Result<Vec<Target>, io::Error> {
+    let mut command = Command::new("cargo");
+    command
+        .arg("build")
+        .arg("--message-format=json")
+        .arg("--manifest-path")
+        .arg(manifest.as_ref());
+    match target {
+        Some(target) => target.apply(&mut command),
+        None => {
+            command.arg("--bins").arg("--examples").arg("--benches");
+        }
+    }
+    if build == Build::Release {
+        command.arg("--release");
+    }
+    features.apply(&mut command);
+    packages.apply(&mut command);
+    targets_from(command)
 }
 
-/// Query the crate metadata of the given `Cargo.toml`.
+/// Query all test harnesses (`cargo test`/`cargo bench` binaries) of the
+/// crate denoted by the given `Cargo.toml`, without running them.
 ///
-/// This collects the metadata of the crate denoted by the `path` using the
-/// [`cargo_metadata()`](fn.cargo_metadata.html) function. Its output is then
-/// parsed into the `Metadata` structure.
+/// This builds with `cargo test --no-run --message-format=json`, which
+/// reports the same kind of `compiler-artifact` messages as `cargo build`,
+/// but also includes the `target.kind == "test"` harnesses selected by
+/// `test_filter`. Each resulting executable can then be run under `valgrind`
+/// exactly like any other [`Target`](enum.Target.html). Other executables the
+/// command builds (e.g. binaries needed by integration tests) are skipped, as
+/// are output lines that cannot be parsed as a build message.
 ///
 /// # Errors
-/// This function either fails because of an error of the `cargo_metadata()`
-/// function or due to an invalid output by it, that could not successfully be
-/// parsed.
-fn metadata<P: AsRef<Path>>(path: P) -> Result<metadata::Metadata, io::Error> {
-    let metadata = cargo_metadata(path)?;
-    serde_json::from_str(&metadata)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Invalid metadata: {}", e)))
+/// This function fails, if `cargo test --no-run` could not be spawned or if
+/// it exits with a non-zero status.
+pub fn test_targets<P: AsRef<Path>>(
+    manifest: P,
+    build: Build,
+    features: &Features,
+    packages: &PackageSelection,
+    test_filter: &TestFilter,
+) -> Result<Vec<Target>, io::Error> {
+    let mut command = Command::new("cargo");
+    command
+        .arg("test")
+        .arg("--no-run")
+        .arg("--message-format=json")
+        .arg("--manifest-path")
+        .arg(manifest.as_ref());
+    if build == Build::Release {
+        command.arg("--release");
+    }
+    features.apply(&mut command);
+    packages.apply(&mut command);
+    test_filter.apply(&mut command);
+    Ok(targets_from(command)?
+        .into_iter()
+        .filter(|target| match target {
+            Target::Test { .. } => true,
+            _ => false,
+        })
+        .collect())
 }
 
-/// Run the `cargo metadata` command and collect its output.
+/// Run the given `cargo build`/`cargo test --no-run` command and collect the
+/// [`Target`](enum.Target.html)s reported on its stdout.
 ///
-/// The `path` has to point to the `Cargo.toml` of which the metadata should be
-/// collected. Metadata of the dependencies is omitted on purpose. The output is
-/// then converted into a `String`.
+/// This is the real implementation of [`targets()`](fn.targets.html) and
+/// [`test_targets()`](fn.test_targets.html). It was split off to allow
+/// building the command line (e.g. to add `--bin` filters) before the build
+/// is actually spawned and its output parsed.
+///
+/// Only `stdout` is captured, and read line by line as it arrives; `stderr`
+/// is left to inherit the terminal, so cargo's own "Compiling ..." progress
+/// stays visible for the (potentially long) discovery build instead of the
+/// tool appearing to hang until it finishes.
 ///
 /// # Errors
-/// This function can fail either because the `cargo metadata` command could not
-/// be spawned, the command failed (i.e. it was executed but returned a non-zero
-/// exit code) or the string printed to stdout was not valid UTF-8.
-fn cargo_metadata<P: AsRef<Path>>(path: P) -> Result<String, io::Error> {
-    let output = Command::new("cargo")
-        .arg("metadata")
-        .arg("--format-version=1")
-        .arg("--no-deps")
-        .arg("--offline")
-        .arg("--manifest-path")
-        .arg(path.as_ref())
-        .output()?;
+/// This function fails, if `command` could not be spawned, if it exits with
+/// a non-zero status, or if its stdout could not be captured.
+fn targets_from(mut command: Command) -> Result<Vec<Target>, io::Error> {
+    let description = format!("{:?}", command);
+    let mut child = command.stdout(Stdio::piped()).spawn()?;
+    let stdout = child.stdout.take().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::Other, "Could not capture cargo's stdout")
+    })?;
 
-    if output.status.success() {
-        String::from_utf8(output.stdout)
-            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Non-UTF-8 string"))
+    let targets = BufReader::new(stdout)
+        .lines()
+        .filter_map(|line| line.ok())
+        .filter_map(|line| serde_json::from_str::<BuildMessage>(&line).ok())
+        .filter_map(BuildMessage::into_target)
+        .collect();
+
+    let status = child.wait()?;
+    if status.success() {
+        Ok(targets)
     } else {
-        let msg = String::from_utf8_lossy(&output.stderr);
-        let msg = msg.trim_start_matches("error: ").trim_end();
         Err(io::Error::new(
             io::ErrorKind::Other,
-            format!("cargo command failed: {}", msg),
+            format!("{} failed; see cargo's output above", description),
         ))
     }
-}
\ No newline at end of file
+}
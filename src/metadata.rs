@@ -0,0 +1,35 @@
+//! Shared vocabulary for the kinds of build target `cargo` can report.
+use serde::Deserialize;
+
+/// The kind of a build target, as reported in the `"kind"` array of a target.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Kind {
+    /// A normal binary (`src/bin/*.rs` or the `[[bin]]` crate binary).
+    Bin,
+    /// An example, built from `examples/*.rs`.
+    Example,
+    /// A benchmark harness, built from `benches/*.rs`.
+    Bench,
+    /// An integration test harness, built from `tests/*.rs`. A library's
+    /// inline `#[test]` module is reported with the library's own kind.
+    Test,
+    /// A library target.
+    Lib,
+    /// A procedural macro library.
+    ProcMacro,
+    /// A dynamic library (`dylib`).
+    #[serde(rename = "dylib")]
+    DyLib,
+    /// A C-compatible dynamic library (`cdylib`).
+    #[serde(rename = "cdylib")]
+    CDyLib,
+    /// A static library (`staticlib`).
+    #[serde(rename = "staticlib")]
+    StaticLib,
+    /// A static Rust library (`rlib`).
+    #[serde(rename = "rlib")]
+    RLib,
+    /// The package's build script.
+    CustomBuild,
+}
@@ -0,0 +1,119 @@
+//! Unit tests for the target discovery and feature selection logic.
+use super::*;
+
+#[test]
+fn compiler_artifact_binary_becomes_target() {
+    let message = r#"{
+        "reason": "compiler-artifact",
+        "executable": "/repo/target/debug/cargo-valgrind",
+        "target": { "name": "cargo-valgrind", "kind": ["bin"] },
+        "profile": { "test": false }
+    }"#;
+    let message: BuildMessage = serde_json::from_str(message).unwrap();
+    assert_eq!(
+        message.into_target(),
+        Some(Target::Binary {
+            name: "cargo-valgrind".to_string(),
+            path: PathBuf::from("/repo/target/debug/cargo-valgrind"),
+        })
+    );
+}
+
+#[test]
+fn compiler_artifact_without_executable_is_ignored() {
+    let message = r#"{
+        "reason": "compiler-artifact",
+        "executable": null,
+        "target": { "name": "cargo-valgrind", "kind": ["lib"] }
+    }"#;
+    let message: BuildMessage = serde_json::from_str(message).unwrap();
+    assert_eq!(message.into_target(), None);
+}
+
+#[test]
+fn compiler_artifact_lib_unit_test_harness_becomes_test_target() {
+    let message = r#"{
+        "reason": "compiler-artifact",
+        "executable": "/repo/target/debug/deps/mylib-abcdef",
+        "target": { "name": "mylib", "kind": ["lib"] },
+        "profile": { "test": true }
+    }"#;
+    let message: BuildMessage = serde_json::from_str(message).unwrap();
+    assert_eq!(
+        message.into_target(),
+        Some(Target::Test {
+            name: "mylib".to_string(),
+            path: PathBuf::from("/repo/target/debug/deps/mylib-abcdef"),
+        })
+    );
+}
+
+#[test]
+fn non_artifact_message_is_ignored() {
+    let message = r#"{
+        "reason": "compiler-message",
+        "executable": null,
+        "target": { "name": "cargo-valgrind", "kind": ["bin"] }
+    }"#;
+    let message: BuildMessage = serde_json::from_str(message).unwrap();
+    assert_eq!(message.into_target(), None);
+}
+
+#[test]
+fn features_apply_their_arguments() {
+    let features = Features {
+        features: vec!["foo".into(), "bar".into()],
+        all_features: true,
+        no_default_features: true,
+    };
+    let mut command = Command::new("cargo");
+    features.apply(&mut command);
+    let args: Vec<_> = command
+        .get_args()
+        .map(|arg| arg.to_str().unwrap())
+        .collect();
+    assert_eq!(
+        args,
+        vec!["--features", "foo,bar", "--all-features", "--no-default-features"]
+    );
+}
+
+#[test]
+fn no_features_selected_applies_nothing() {
+    let mut command = Command::new("cargo");
+    Features::default().apply(&mut command);
+    assert!(command.get_args().next().is_none());
+}
+
+#[test]
+fn compiler_artifact_test_becomes_target() {
+    let message = r#"{
+        "reason": "compiler-artifact",
+        "executable": "/repo/target/debug/deps/integration-abcdef",
+        "target": { "name": "integration", "kind": ["test"] },
+        "profile": { "test": true }
+    }"#;
+    let message: BuildMessage = serde_json::from_str(message).unwrap();
+    assert_eq!(
+        message.into_target(),
+        Some(Target::Test {
+            name: "integration".to_string(),
+            path: PathBuf::from("/repo/target/debug/deps/integration-abcdef"),
+        })
+    );
+}
+
+#[test]
+fn test_filter_applies_its_arguments() {
+    let filter = TestFilter {
+        tests: vec!["integration".into()],
+        all_tests: true,
+    };
+    let mut command = Command::new("cargo");
+    filter.apply(&mut command);
+    let args: Vec<_> = command
+        .get_args()
+        .map(|arg| arg.to_str().unwrap())
+        .collect();
+    assert_eq!(args, vec!["--tests", "--test", "integration"]);
+}